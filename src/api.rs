@@ -0,0 +1,122 @@
+use std::{convert::Infallible, sync::Arc};
+
+use serde::Deserialize;
+use tokio::sync::Mutex;
+use warp::{http::StatusCode, Filter, Rejection, Reply};
+
+use crate::{broadcast_inventory_state, check_now, AppState, InventoryState, Match, SharedBot};
+
+/// Body accepted by `POST /items`. `in_stock` is deliberately absent - it's
+/// derived state the poller owns, so a freshly added product always starts
+/// out of stock rather than trusting (or requiring) a caller-supplied value.
+#[derive(Debug, Deserialize)]
+struct NewProduct {
+    product: String,
+    vendor: String,
+    url: String,
+    matches: Vec<Match>,
+}
+
+fn with_state(
+    app_state: Arc<Mutex<AppState>>,
+) -> impl Filter<Extract = (Arc<Mutex<AppState>>,), Error = Infallible> + Clone {
+    warp::any().map(move || app_state.clone())
+}
+
+fn with_bot(bot: SharedBot) -> impl Filter<Extract = (SharedBot,), Error = Infallible> + Clone {
+    warp::any().map(move || bot.clone())
+}
+
+async fn list_items(app_state: Arc<Mutex<AppState>>) -> Result<Box<dyn Reply>, Rejection> {
+    match app_state.lock().await.store.load_states() {
+        Ok(states) => Ok(Box::new(warp::reply::json(&states))),
+        Err(err) => {
+            log::error!("Failed to load items via API: {:?}", err);
+            Ok(Box::new(StatusCode::INTERNAL_SERVER_ERROR))
+        }
+    }
+}
+
+async fn add_item(
+    new_product: NewProduct,
+    app_state: Arc<Mutex<AppState>>,
+) -> Result<impl Reply, Rejection> {
+    let state = InventoryState {
+        product: new_product.product,
+        vendor: new_product.vendor,
+        url: new_product.url,
+        in_stock: false,
+        matches: new_product.matches,
+    };
+    match app_state.lock().await.store.add_product(&state) {
+        Ok(()) => Ok(StatusCode::CREATED),
+        Err(err) => {
+            log::error!("Failed to add product via API: {:?}", err);
+            Ok(StatusCode::BAD_REQUEST)
+        }
+    }
+}
+
+async fn delete_item(
+    product: String,
+    app_state: Arc<Mutex<AppState>>,
+) -> Result<impl Reply, Rejection> {
+    match app_state.lock().await.store.remove_product(&product) {
+        Ok(true) => Ok(StatusCode::NO_CONTENT),
+        Ok(false) => Ok(StatusCode::NOT_FOUND),
+        Err(err) => {
+            log::error!("Failed to remove product via API: {:?}", err);
+            Ok(StatusCode::INTERNAL_SERVER_ERROR)
+        }
+    }
+}
+
+async fn trigger_check(
+    app_state: Arc<Mutex<AppState>>,
+    bot: SharedBot,
+) -> Result<impl Reply, Rejection> {
+    if let Some(states) = check_now(&app_state).await {
+        let subscriptions = app_state.lock().await.subscriptions.clone();
+        broadcast_inventory_state("State Changed", &bot, &subscriptions, &states).await;
+    }
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Management API: `GET/POST /items`, `DELETE /items/{product}`, and
+/// `POST /check`, so the inventory can be edited and polled from a script
+/// instead of hand-editing YAML and restarting the bot.
+pub fn routes(
+    app_state: Arc<Mutex<AppState>>,
+    bot: SharedBot,
+) -> impl Filter<Extract = impl Reply, Error = Rejection> + Clone {
+    let items = warp::path("items");
+
+    let list = items
+        .and(warp::get())
+        .and(warp::path::end())
+        .and(with_state(app_state.clone()))
+        .and_then(list_items);
+
+    let add = items
+        .and(warp::post())
+        .and(warp::path::end())
+        .and(warp::body::json())
+        .and(with_state(app_state.clone()))
+        .and_then(add_item);
+
+    let delete = items
+        .and(warp::delete())
+        .and(warp::path::param())
+        .and(warp::path::end())
+        .and(with_state(app_state.clone()))
+        .and_then(delete_item);
+
+    let check = warp::path("check")
+        .and(warp::post())
+        .and(warp::path::end())
+        .and(with_state(app_state))
+        .and(with_bot(bot))
+        .and_then(trigger_check);
+
+    list.or(add).or(delete).or(check)
+}
@@ -1,10 +1,28 @@
+mod api;
+mod store;
+
 use clap::Parser;
+use futures::future;
 use log;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
-use std::{fmt::Write, io::Write as IoWrite, time::Duration};
-use teloxide::{adaptors::DefaultParseMode, prelude::*, types::ParseMode};
-use tokio::{task, time};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Write,
+    io::Write as IoWrite,
+    sync::Arc,
+    time::Duration,
+};
+use teloxide::{
+    adaptors::DefaultParseMode, dispatching::UpdateFilterExt, prelude::*, types::ParseMode,
+    utils::command::BotCommands,
+};
+use tokio::{sync::Mutex, task, time};
+
+use store::Store;
+
+type SharedBot = DefaultParseMode<AutoSend<Bot>>;
+type Subscriptions = HashMap<ChatId, HashSet<String>>;
 
 #[derive(Parser)]
 #[clap(about = "Inventory Alerts", long_about = None, version, about)]
@@ -12,17 +30,39 @@ struct Cli {
     #[clap(short, long, value_parser, default_value_t = log::LevelFilter::Info)]
     log_level: log::LevelFilter,
 
+    /// YAML file used to seed the database the first time it's empty.
     #[clap(short, long, default_value = "matches.yml")]
     match_file: String,
 
+    /// SQLite database that is the live source of truth for inventory state.
+    #[clap(short, long, default_value = "inventory.db")]
+    db_file: String,
+
+    #[clap(short, long, default_value = "subscriptions.yml")]
+    subscription_file: String,
+
     #[clap(short, long, default_value_t = 60.0)]
     update_period_s: f64,
 
-    #[clap(required = true)]
-    chat_id: i64,
+    /// Address the management HTTP API listens on.
+    #[clap(short, long, default_value = "127.0.0.1:3000")]
+    bind_addr: std::net::SocketAddr,
+
+    /// Format match rules are persisted in, mirroring teloxide's swappable
+    /// dialogue serializers. Regexes don't round-trip losslessly through
+    /// every serde format, so this is explicit rather than inferred.
+    #[clap(long, value_enum, default_value = "yaml")]
+    serializer: SerializerKind,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+enum SerializerKind {
+    Yaml,
+    Cbor,
+    Bincode,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 #[serde(rename_all = "camelCase")]
 enum Match {
     #[serde(with = "serde_regex")]
@@ -31,9 +71,49 @@ enum Match {
     NotRegex(Regex),
     Contains(String),
     DoesNotContain(String),
+    Any(Vec<Match>),
+    All(Vec<Match>),
+    Not(Box<Match>),
+}
+
+/// Rejects an empty `All`/`Any` (meaningless - vacuously true/false) and
+/// recurses into nested rules, so a bad rule is caught at load time with the
+/// product it belongs to rather than silently evaluating to a vacuous
+/// `true` (`All`) or `false` (`Any`) later.
+fn validate_match(product: &str, text_match: &Match) -> Result<()> {
+    match text_match {
+        Match::All(rules) | Match::Any(rules) => {
+            if rules.is_empty() {
+                return Err(Error::InvalidRule(format!(
+                    "{}: All/Any match rule must not be empty",
+                    product
+                )));
+            }
+            rules
+                .iter()
+                .try_for_each(|rule| validate_match(product, rule))
+        }
+        Match::Not(rule) => validate_match(product, rule),
+        Match::Regex(_) | Match::NotRegex(_) | Match::Contains(_) | Match::DoesNotContain(_) => {
+            Ok(())
+        }
+    }
+}
+
+/// Evaluates a match rule tree against the fetched page body.
+fn evaluate_match(text_match: &Match, body: &str) -> bool {
+    match text_match {
+        Match::Regex(val) => val.is_match(body),
+        Match::NotRegex(val) => !val.is_match(body),
+        Match::Contains(val) => body.contains(val),
+        Match::DoesNotContain(val) => !body.contains(val),
+        Match::Any(rules) => rules.iter().any(|rule| evaluate_match(rule, body)),
+        Match::All(rules) => rules.iter().all(|rule| evaluate_match(rule, body)),
+        Match::Not(rule) => !evaluate_match(rule, body),
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize)]
 struct InventoryState {
     product: String,
     vendor: String,
@@ -42,16 +122,15 @@ struct InventoryState {
     matches: Vec<Match>,
 }
 
-enum MatchUpdate {
-    NoChange,
-    Updated(Vec<InventoryState>),
-}
-
 #[derive(Debug)]
 enum Error {
     IO(std::io::Error),
     SerdeYaml(serde_yaml::Error),
+    SerdeCbor(serde_cbor::Error),
+    Bincode(Box<bincode::ErrorKind>),
     Reqwest(reqwest::Error),
+    Sqlite(rusqlite::Error),
+    InvalidRule(String),
 }
 
 type Result<T> = std::result::Result<T, Error>;
@@ -74,50 +153,191 @@ impl From<reqwest::Error> for Error {
     }
 }
 
-async fn update_state(fname: &str) -> Result<MatchUpdate> {
+impl From<rusqlite::Error> for Error {
+    fn from(err: rusqlite::Error) -> Error {
+        Error::Sqlite(err)
+    }
+}
+
+impl From<serde_cbor::Error> for Error {
+    fn from(err: serde_cbor::Error) -> Error {
+        Error::SerdeCbor(err)
+    }
+}
+
+impl From<Box<bincode::ErrorKind>> for Error {
+    fn from(err: Box<bincode::ErrorKind>) -> Error {
+        Error::Bincode(err)
+    }
+}
+
+/// State shared between the background polling task and the interactive
+/// command handlers, guarded by a single mutex since both sides only ever
+/// touch it for the duration of a read, a store write, or a subscription update.
+struct AppState {
+    store: Store,
+    client: reqwest::Client,
+    subscription_file: String,
+    subscriptions: Subscriptions,
+    paused: bool,
+}
+
+fn load_subscriptions(fname: &str) -> Subscriptions {
+    std::fs::read_to_string(fname)
+        .ok()
+        .and_then(|yaml_str| serde_yaml::from_str(&yaml_str).ok())
+        .unwrap_or_default()
+}
+
+fn save_subscriptions(fname: &str, subscriptions: &Subscriptions) -> Result<()> {
+    let yaml_str = serde_yaml::to_string(subscriptions)?;
+    std::fs::write(fname, yaml_str)?;
+    Ok(())
+}
+
+/// Renders a unix timestamp as a rough "N units ago" string for `/history`.
+fn humanize_age(changed_at: i64) -> String {
+    let now = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64;
+    let age = (now - changed_at).max(0);
+    match age {
+        0..=59 => format!("{}s ago", age),
+        60..=3599 => format!("{}m ago", age / 60),
+        3600..=86399 => format!("{}h ago", age / 3600),
+        _ => format!("{}d ago", age / 86400),
+    }
+}
+
+#[derive(BotCommands, Clone)]
+#[command(rename_rule = "lowercase", description = "Supported commands:")]
+enum Command {
+    #[command(description = "list the products this chat is subscribed to")]
+    List,
+    #[command(description = "show the stock status for a single product")]
+    Status(String),
+    #[command(description = "show recent stock transitions for a product")]
+    History(String),
+    #[command(description = "subscribe this chat to a product's stock updates")]
+    Subscribe(String),
+    #[command(description = "unsubscribe this chat from a product's stock updates")]
+    Unsubscribe(String),
+    #[command(description = "pause the background polling loop")]
+    Pause,
+    #[command(description = "resume the background polling loop")]
+    Resume,
+    #[command(description = "force an immediate stock check")]
+    Check,
+}
+
+const MAX_FETCH_RETRIES: u32 = 3;
+const FETCH_BACKOFF_BASE: Duration = Duration::from_millis(250);
+
+/// Fetches a single product's page, retrying transient failures (timeouts,
+/// 5xx) with exponential backoff and jitter. Returns `None` once retries are
+/// exhausted or the failure isn't transient, after logging the product, URL,
+/// and status/error so the failure is diagnosable.
+async fn fetch_product_body(client: &reqwest::Client, product: &str, url: &str) -> Option<String> {
+    for attempt in 0..=MAX_FETCH_RETRIES {
+        match client.get(url).send().await {
+            Ok(response) => {
+                let status = response.status();
+                if status.is_success() {
+                    return match response.text().await {
+                        Ok(body) => Some(body),
+                        Err(err) => {
+                            log::error!(
+                                "Failed to read response body for {} ({}): {}",
+                                product,
+                                url,
+                                err
+                            );
+                            None
+                        }
+                    };
+                }
+                if status.is_server_error() && attempt < MAX_FETCH_RETRIES {
+                    log::debug!(
+                        "Transient HTTP {} for {} ({}), retrying (attempt {})",
+                        status,
+                        product,
+                        url,
+                        attempt + 1
+                    );
+                    sleep_with_jitter(attempt).await;
+                    continue;
+                }
+                log::error!("Fetch failed for {} ({}): HTTP {}", product, url, status);
+                return None;
+            }
+            Err(err) if err.is_timeout() && attempt < MAX_FETCH_RETRIES => {
+                log::debug!(
+                    "Timed out fetching {} ({}), retrying (attempt {})",
+                    product,
+                    url,
+                    attempt + 1
+                );
+                sleep_with_jitter(attempt).await;
+                continue;
+            }
+            Err(err) => {
+                log::error!("Fetch failed for {} ({}): {}", product, url, err);
+                return None;
+            }
+        }
+    }
+    None
+}
+
+async fn sleep_with_jitter(attempt: u32) {
+    let backoff = FETCH_BACKOFF_BASE * 2u32.pow(attempt);
+    let jitter = Duration::from_millis(rand::random::<u64>() % 250);
+    time::sleep(backoff + jitter).await;
+}
+
+/// Fetches every product concurrently and diffs the results against
+/// `states`, without touching the store - this is the slow, retry-laden
+/// part of a check cycle, so it's kept off the `AppState` mutex entirely.
+async fn fetch_and_diff(
+    mut states: Vec<InventoryState>,
+    client: &reqwest::Client,
+) -> (Vec<InventoryState>, Vec<(String, bool, bool)>) {
     log::debug!("Start Update State");
 
-    let yaml_str = std::fs::read_to_string(fname.clone())?;
-    let mut states: Vec<InventoryState> = serde_yaml::from_str(&yaml_str)?;
-
-    let mut state_changed = false;
-    for state in states.iter_mut() {
-        let body = reqwest::Client::builder()
-            .user_agent("curl/7.79.1")
-            .build()?
-            .get(state.url.clone())
-            .send()
-            .await?
-            .text()
-            .await?;
-
-        let in_stock = state.matches.iter().all(|text_match| match text_match {
-            Match::Regex(val) => val.is_match(&body),
-            Match::NotRegex(val) => !val.is_match(&body),
-            Match::Contains(val) => body.contains(val),
-            Match::DoesNotContain(val) => !body.contains(val),
-        });
+    let bodies = future::join_all(
+        states
+            .iter()
+            .map(|state| fetch_product_body(client, &state.product, &state.url)),
+    )
+    .await;
+
+    let mut transitions = Vec::new();
+    for (state, body) in states.iter_mut().zip(bodies) {
+        let body = match body {
+            Some(body) => body,
+            None => continue,
+        };
+
+        let in_stock = state
+            .matches
+            .iter()
+            .all(|text_match| evaluate_match(text_match, &body));
 
         if state.in_stock != in_stock {
+            transitions.push((state.product.clone(), state.in_stock, in_stock));
             state.in_stock = in_stock;
-            state_changed = true;
         }
     }
 
-    if state_changed {
-        let yaml_str = serde_yaml::to_string(&states)?;
-        std::fs::write(fname.to_string(), yaml_str)?;
-        Ok(MatchUpdate::Updated(states))
-    } else {
-        Ok(MatchUpdate::NoChange)
-    }
+    (states, transitions)
 }
 
 async fn send_inventory_state(
     header: &str,
-    bot: &DefaultParseMode<AutoSend<Bot>>,
+    bot: &SharedBot,
     chat_id: ChatId,
-    states: Vec<InventoryState>,
+    states: &[InventoryState],
 ) {
     let mut data: String;
     data = header.to_string();
@@ -144,6 +364,212 @@ async fn send_inventory_state(
     }
 }
 
+/// Sends `states`, split per chat, to only the chats subscribed to each
+/// product, rather than blasting every tracked product to every chat.
+async fn broadcast_inventory_state(
+    header: &str,
+    bot: &SharedBot,
+    subscriptions: &Subscriptions,
+    states: &[InventoryState],
+) {
+    for (chat_id, products) in subscriptions {
+        let relevant: Vec<InventoryState> = states
+            .iter()
+            .filter(|state| products.contains(&state.product))
+            .cloned()
+            .collect();
+        if !relevant.is_empty() {
+            send_inventory_state(header, bot, *chat_id, &relevant).await;
+        }
+    }
+}
+
+/// Runs a single check cycle against the shared app state and returns the
+/// refreshed states if anything changed, for both the interval loop and the
+/// `/check` command to report back to subscribers. The mutex is only held
+/// to load the current states and, afterwards, to persist any transitions -
+/// the concurrent fetch and its retry/backoff sleeps run with the lock
+/// released, so `/status`, `/list`, and the management API stay responsive
+/// during a poll cycle.
+async fn check_now(app_state: &Arc<Mutex<AppState>>) -> Option<Vec<InventoryState>> {
+    let (states, client) = {
+        let guard = app_state.lock().await;
+        let states = match guard.store.load_states() {
+            Ok(states) => states,
+            Err(err) => {
+                log::error!("Failed to load state: {:?}", err);
+                return None;
+            }
+        };
+        (states, guard.client.clone())
+    };
+
+    let (states, transitions) = fetch_and_diff(states, &client).await;
+
+    if transitions.is_empty() {
+        log::debug!("State did not change");
+        return None;
+    }
+
+    let mut guard = app_state.lock().await;
+    match guard.store.apply_transitions(&transitions) {
+        Ok(()) => Some(states),
+        Err(err) => {
+            log::error!("Failed to persist state transitions: {:?}", err);
+            None
+        }
+    }
+}
+
+async fn answer(
+    bot: SharedBot,
+    msg: Message,
+    cmd: Command,
+    app_state: Arc<Mutex<AppState>>,
+) -> std::result::Result<(), teloxide::RequestError> {
+    let chat_id = msg.chat.id;
+    match cmd {
+        Command::List => {
+            let guard = app_state.lock().await;
+            let products = guard
+                .subscriptions
+                .get(&chat_id)
+                .cloned()
+                .unwrap_or_default();
+            let mut subscribed = Vec::new();
+            for product in &products {
+                if let Ok(Some(state)) = guard.store.get_state(product) {
+                    subscribed.push(state);
+                }
+            }
+            drop(guard);
+            if subscribed.is_empty() {
+                bot.send_message(
+                    chat_id,
+                    "This chat isn't subscribed to any products yet, use /subscribe <product>",
+                )
+                .await?;
+            } else {
+                send_inventory_state("Subscriptions", &bot, chat_id, &subscribed).await;
+            }
+        }
+        Command::Status(product) => {
+            let product = product.trim();
+            let state = app_state
+                .lock()
+                .await
+                .store
+                .get_state_ci(product)
+                .ok()
+                .flatten();
+            match state {
+                Some(state) => send_inventory_state("Status", &bot, chat_id, &[state]).await,
+                None => {
+                    bot.send_message(chat_id, format!("No tracked product named {}", product))
+                        .await?;
+                }
+            }
+        }
+        Command::History(product) => {
+            let product = product.trim();
+            let history = app_state.lock().await.store.history(product, 10).ok();
+            match history {
+                Some(changes) if !changes.is_empty() => {
+                    let mut data = format!("History for {}", product);
+                    for change in &changes {
+                        write!(
+                            data,
+                            "\n{}: {} to {}",
+                            humanize_age(change.changed_at),
+                            if change.was_in_stock {
+                                "In Stock"
+                            } else {
+                                "Out of Stock"
+                            },
+                            if change.now_in_stock {
+                                "In Stock"
+                            } else {
+                                "Out of Stock"
+                            },
+                        )
+                        .unwrap();
+                    }
+                    data = data.replace("-", "\\-");
+                    bot.send_message(chat_id, data).await?;
+                }
+                _ => {
+                    bot.send_message(chat_id, format!("No history for {}", product))
+                        .await?;
+                }
+            }
+        }
+        Command::Subscribe(product) => {
+            let product = product.trim().to_string();
+            let mut guard = app_state.lock().await;
+            if guard.store.get_state(&product).ok().flatten().is_none() {
+                drop(guard);
+                bot.send_message(chat_id, format!("No tracked product named {}", product))
+                    .await?;
+            } else {
+                guard
+                    .subscriptions
+                    .entry(chat_id)
+                    .or_insert_with(HashSet::new)
+                    .insert(product.clone());
+                let subscription_file = guard.subscription_file.clone();
+                let subscriptions = guard.subscriptions.clone();
+                drop(guard);
+                if let Err(err) = save_subscriptions(&subscription_file, &subscriptions) {
+                    log::error!("Failed to persist subscriptions: {:?}", err);
+                }
+                bot.send_message(chat_id, format!("Subscribed to {}", product))
+                    .await?;
+            }
+        }
+        Command::Unsubscribe(product) => {
+            let product = product.trim().to_string();
+            let mut guard = app_state.lock().await;
+            let removed = guard
+                .subscriptions
+                .get_mut(&chat_id)
+                .map(|products| products.remove(&product))
+                .unwrap_or(false);
+            let subscription_file = guard.subscription_file.clone();
+            let subscriptions = guard.subscriptions.clone();
+            drop(guard);
+            if removed {
+                if let Err(err) = save_subscriptions(&subscription_file, &subscriptions) {
+                    log::error!("Failed to persist subscriptions: {:?}", err);
+                }
+                bot.send_message(chat_id, format!("Unsubscribed from {}", product))
+                    .await?;
+            } else {
+                bot.send_message(chat_id, format!("Not subscribed to {}", product))
+                    .await?;
+            }
+        }
+        Command::Pause => {
+            app_state.lock().await.paused = true;
+            bot.send_message(chat_id, "Polling paused").await?;
+        }
+        Command::Resume => {
+            app_state.lock().await.paused = false;
+            bot.send_message(chat_id, "Polling resumed").await?;
+        }
+        Command::Check => match check_now(&app_state).await {
+            Some(states) => {
+                let subscriptions = app_state.lock().await.subscriptions.clone();
+                broadcast_inventory_state("State Changed", &bot, &subscriptions, &states).await;
+            }
+            None => {
+                bot.send_message(chat_id, "No change since last check")
+                    .await?;
+            }
+        },
+    }
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() {
     let cli_args = Cli::parse();
@@ -167,36 +593,77 @@ async fn main() {
     let bot = Bot::from_env()
         .auto_send()
         .parse_mode(ParseMode::MarkdownV2);
-    let chat_id = ChatId(cli_args.chat_id);
-
-    {
-        let yaml_str = std::fs::read_to_string(cli_args.match_file.clone()).unwrap();
-        let inventory_state: Vec<InventoryState> = serde_yaml::from_str(&yaml_str).unwrap();
-        send_inventory_state("Boot", &bot, chat_id.clone(), inventory_state).await;
-    }
-
-    let forever = task::spawn(async move {
-        let mut interval = time::interval(Duration::from_secs_f64(cli_args.update_period_s));
-
-        loop {
-            interval.tick().await;
-            match update_state(&cli_args.match_file).await {
-                Ok(update) => match update {
-                    MatchUpdate::NoChange => log::debug!("State did not change"),
-                    MatchUpdate::Updated(inventory_state) => {
-                        send_inventory_state(
-                            "State Changed",
-                            &bot,
-                            chat_id.clone(),
-                            inventory_state,
-                        )
-                        .await
-                    }
-                },
-                Err(err) => log::error!("Failed to update state: {:?}", err),
+
+    let subscriptions = load_subscriptions(&cli_args.subscription_file);
+
+    let mut store = Store::open(&cli_args.db_file, cli_args.serializer).unwrap();
+    store.seed_from_yaml(&cli_args.match_file).unwrap();
+
+    let initial_states = store.load_states().unwrap();
+    broadcast_inventory_state("Boot", &bot, &subscriptions, &initial_states).await;
+
+    let client = reqwest::Client::builder()
+        .user_agent("curl/7.79.1")
+        .build()
+        .unwrap();
+
+    let app_state = Arc::new(Mutex::new(AppState {
+        store,
+        client,
+        subscription_file: cli_args.subscription_file.clone(),
+        subscriptions,
+        paused: false,
+    }));
+
+    let polling = {
+        let bot = bot.clone();
+        let app_state = app_state.clone();
+        let update_period_s = cli_args.update_period_s;
+        task::spawn(async move {
+            let mut interval = time::interval(Duration::from_secs_f64(update_period_s));
+
+            loop {
+                interval.tick().await;
+                if app_state.lock().await.paused {
+                    log::debug!("Polling is paused, skipping tick");
+                    continue;
+                }
+                if let Some(states) = check_now(&app_state).await {
+                    let subscriptions = app_state.lock().await.subscriptions.clone();
+                    broadcast_inventory_state("State Changed", &bot, &subscriptions, &states).await;
+                }
             }
-        }
-    });
+        })
+    };
+
+    let api_server = {
+        let bot = bot.clone();
+        let app_state = app_state.clone();
+        let bind_addr = cli_args.bind_addr;
+        task::spawn(async move {
+            warp::serve(api::routes(app_state, bot))
+                .run(bind_addr)
+                .await;
+        })
+    };
+
+    let commands = {
+        let handler = Update::filter_message()
+            .filter_command::<Command>()
+            .endpoint(answer);
+
+        task::spawn(async move {
+            Dispatcher::builder(bot, handler)
+                .dependencies(dptree::deps![app_state])
+                .enable_ctrlc_handler()
+                .build()
+                .dispatch()
+                .await;
+        })
+    };
 
-    forever.await.unwrap()
+    let (polling, api_server, commands) = tokio::join!(polling, api_server, commands);
+    polling.unwrap();
+    api_server.unwrap();
+    commands.unwrap();
 }
@@ -0,0 +1,301 @@
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::{validate_match, Error, InventoryState, Match, Result, SerializerKind};
+
+/// A single `in_stock` transition recorded for `/history`.
+#[derive(Debug, Clone)]
+pub struct StockChange {
+    pub product: String,
+    /// Unix timestamp (seconds) the transition was observed at.
+    pub changed_at: i64,
+    pub was_in_stock: bool,
+    pub now_in_stock: bool,
+}
+
+/// SQLite-backed store for inventory state. Reads are point queries against
+/// `products`/`match_rules`; writes to `in_stock` happen through
+/// [`Store::apply_transitions`], which updates each changed row and records
+/// a `stock_changes` entry inside a single transaction. Match rules are
+/// persisted as encoded blobs using whichever [`SerializerKind`] the store
+/// was opened with, since regexes don't round-trip losslessly through every
+/// serde format.
+pub struct Store {
+    conn: Connection,
+    serializer: SerializerKind,
+}
+
+impl Store {
+    pub fn open(path: &str, serializer: SerializerKind) -> Result<Store> {
+        let conn = Connection::open(path)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS products (
+                product  TEXT PRIMARY KEY,
+                vendor   TEXT NOT NULL,
+                url      TEXT NOT NULL,
+                in_stock INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS match_rules (
+                product    TEXT NOT NULL REFERENCES products(product) ON DELETE CASCADE,
+                position   INTEGER NOT NULL,
+                rule_bytes BLOB NOT NULL,
+                PRIMARY KEY (product, position)
+            );
+            CREATE TABLE IF NOT EXISTS stock_changes (
+                id            INTEGER PRIMARY KEY AUTOINCREMENT,
+                product       TEXT NOT NULL,
+                changed_at    INTEGER NOT NULL,
+                was_in_stock  INTEGER NOT NULL,
+                now_in_stock  INTEGER NOT NULL
+            );",
+        )?;
+        Ok(Store { conn, serializer })
+    }
+
+    /// Imports `yaml_path` as the initial set of products, but only the
+    /// first time the database is empty, so re-running against an existing
+    /// `inventory.db` is a no-op. The seed file is always YAML regardless of
+    /// the store's persisted serializer.
+    pub fn seed_from_yaml(&mut self, yaml_path: &str) -> Result<()> {
+        let count: i64 = self
+            .conn
+            .query_row("SELECT COUNT(*) FROM products", [], |row| row.get(0))?;
+        if count > 0 {
+            return Ok(());
+        }
+
+        let yaml_str = std::fs::read_to_string(yaml_path)?;
+        let states: Vec<InventoryState> = serde_yaml::from_str(&yaml_str)?;
+        for state in &states {
+            for rule in &state.matches {
+                validate_match(&state.product, rule)?;
+            }
+        }
+
+        let tx = self.conn.transaction()?;
+        for state in &states {
+            tx.execute(
+                "INSERT INTO products (product, vendor, url, in_stock) VALUES (?1, ?2, ?3, ?4)",
+                params![state.product, state.vendor, state.url, state.in_stock],
+            )?;
+            for (position, rule) in state.matches.iter().enumerate() {
+                tx.execute(
+                    "INSERT INTO match_rules (product, position, rule_bytes) VALUES (?1, ?2, ?3)",
+                    params![
+                        state.product,
+                        position as i64,
+                        self.serializer.encode(rule)?
+                    ],
+                )?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn load_states(&self) -> Result<Vec<InventoryState>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT product, vendor, url, in_stock FROM products ORDER BY product")?;
+        let rows = stmt
+            .query_map([], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, String>(2)?,
+                    row.get::<_, bool>(3)?,
+                ))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        rows.into_iter()
+            .map(|(product, vendor, url, in_stock)| {
+                let matches = self.load_match_rules(&product)?;
+                Ok(InventoryState {
+                    product,
+                    vendor,
+                    url,
+                    in_stock,
+                    matches,
+                })
+            })
+            .collect()
+    }
+
+    /// Point query for a single product, used by `/subscribe` instead of
+    /// loading the whole table. Exact match, since subscriptions are keyed
+    /// off the product names already on file.
+    pub fn get_state(&self, product: &str) -> Result<Option<InventoryState>> {
+        self.get_state_where("product = ?1", product)
+    }
+
+    /// Point query for a single product by case-insensitive name, used by
+    /// `/status` so `/status widget` still finds a product stored as
+    /// `Widget`.
+    pub fn get_state_ci(&self, product: &str) -> Result<Option<InventoryState>> {
+        self.get_state_where("product = ?1 COLLATE NOCASE", product)
+    }
+
+    fn get_state_where(&self, predicate: &str, product: &str) -> Result<Option<InventoryState>> {
+        let row = self
+            .conn
+            .query_row(
+                &format!(
+                    "SELECT product, vendor, url, in_stock FROM products WHERE {}",
+                    predicate
+                ),
+                params![product],
+                |row| {
+                    Ok((
+                        row.get::<_, String>(0)?,
+                        row.get::<_, String>(1)?,
+                        row.get::<_, String>(2)?,
+                        row.get::<_, bool>(3)?,
+                    ))
+                },
+            )
+            .optional()?;
+
+        row.map(|(product, vendor, url, in_stock)| {
+            let matches = self.load_match_rules(&product)?;
+            Ok(InventoryState {
+                product,
+                vendor,
+                url,
+                in_stock,
+                matches,
+            })
+        })
+        .transpose()
+    }
+
+    fn load_match_rules(&self, product: &str) -> Result<Vec<Match>> {
+        let mut stmt = self
+            .conn
+            .prepare("SELECT rule_bytes FROM match_rules WHERE product = ?1 ORDER BY position")?;
+        let rules = stmt
+            .query_map(params![product], |row| row.get::<_, Vec<u8>>(0))?
+            .map(|rule_bytes| self.serializer.decode(&rule_bytes?))
+            .collect();
+        rules
+    }
+
+    /// Applies a batch of `(product, was_in_stock, now_in_stock)` transitions
+    /// inside a single transaction: one targeted `UPDATE` per product plus a
+    /// `stock_changes` row so `/history` can report on it later.
+    pub fn apply_transitions(&mut self, transitions: &[(String, bool, bool)]) -> Result<()> {
+        let changed_at = unix_now();
+        let tx = self.conn.transaction()?;
+        for (product, was_in_stock, now_in_stock) in transitions {
+            tx.execute(
+                "UPDATE products SET in_stock = ?1 WHERE product = ?2",
+                params![now_in_stock, product],
+            )?;
+            tx.execute(
+                "INSERT INTO stock_changes (product, changed_at, was_in_stock, now_in_stock)
+                 VALUES (?1, ?2, ?3, ?4)",
+                params![product, changed_at, was_in_stock, now_in_stock],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Most recent transitions for `product`, newest first. Matches
+    /// case-insensitively, like [`Store::get_state_ci`], so `/history widget`
+    /// finds a product stored as `Widget`.
+    pub fn history(&self, product: &str, limit: u32) -> Result<Vec<StockChange>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT product, changed_at, was_in_stock, now_in_stock FROM stock_changes
+             WHERE product = ?1 COLLATE NOCASE ORDER BY changed_at DESC LIMIT ?2",
+        )?;
+        let changes = stmt
+            .query_map(params![product, limit], |row| {
+                Ok(StockChange {
+                    product: row.get(0)?,
+                    changed_at: row.get(1)?,
+                    was_in_stock: row.get(2)?,
+                    now_in_stock: row.get(3)?,
+                })
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()
+            .map_err(Error::from);
+        changes
+    }
+
+    /// Inserts or replaces a product and its match rules, used by the
+    /// management API. Each rule is schema-validated (no empty `All`/`Any`)
+    /// before anything is written, and the failure names the product.
+    pub fn add_product(&mut self, state: &InventoryState) -> Result<()> {
+        for rule in &state.matches {
+            validate_match(&state.product, rule)?;
+        }
+
+        let tx = self.conn.transaction()?;
+        tx.execute(
+            "INSERT OR REPLACE INTO products (product, vendor, url, in_stock) VALUES (?1, ?2, ?3, ?4)",
+            params![state.product, state.vendor, state.url, state.in_stock],
+        )?;
+        tx.execute(
+            "DELETE FROM match_rules WHERE product = ?1",
+            params![state.product],
+        )?;
+        for (position, rule) in state.matches.iter().enumerate() {
+            tx.execute(
+                "INSERT INTO match_rules (product, position, rule_bytes) VALUES (?1, ?2, ?3)",
+                params![
+                    state.product,
+                    position as i64,
+                    self.serializer.encode(rule)?
+                ],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Removes a product and its match rules and history. Returns whether a
+    /// product was actually removed.
+    pub fn remove_product(&mut self, product: &str) -> Result<bool> {
+        let tx = self.conn.transaction()?;
+        tx.execute(
+            "DELETE FROM match_rules WHERE product = ?1",
+            params![product],
+        )?;
+        tx.execute(
+            "DELETE FROM stock_changes WHERE product = ?1",
+            params![product],
+        )?;
+        let removed = tx.execute("DELETE FROM products WHERE product = ?1", params![product])?;
+        tx.commit()?;
+        Ok(removed > 0)
+    }
+}
+
+impl SerializerKind {
+    fn encode(&self, rule: &Match) -> Result<Vec<u8>> {
+        match self {
+            SerializerKind::Yaml => Ok(serde_yaml::to_string(rule)?.into_bytes()),
+            SerializerKind::Cbor => Ok(serde_cbor::to_vec(rule)?),
+            SerializerKind::Bincode => Ok(bincode::serialize(rule)?),
+        }
+    }
+
+    fn decode(&self, bytes: &[u8]) -> Result<Match> {
+        match self {
+            SerializerKind::Yaml => {
+                let rule_str = std::str::from_utf8(bytes)
+                    .map_err(|err| Error::InvalidRule(format!("non-UTF8 YAML rule: {}", err)))?;
+                Ok(serde_yaml::from_str(rule_str)?)
+            }
+            SerializerKind::Cbor => Ok(serde_cbor::from_slice(bytes)?),
+            SerializerKind::Bincode => Ok(bincode::deserialize(bytes)?),
+        }
+    }
+}
+
+fn unix_now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap()
+        .as_secs() as i64
+}